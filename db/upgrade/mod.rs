@@ -36,6 +36,7 @@ use crate::db;
 use failure::{Error, bail};
 use log::info;
 use rusqlite::params;
+use std::path::{Path, PathBuf};
 
 mod v0_to_v1;
 mod v1_to_v2;
@@ -45,12 +46,186 @@ mod v4_to_v5;
 
 const UPGRADE_NOTES: &'static str =
     concat!("upgraded using moonfire-db ", env!("CARGO_PKG_VERSION"));
+const DOWNGRADE_NOTES: &'static str =
+    concat!("downgraded using moonfire-db ", env!("CARGO_PKG_VERSION"));
 
 #[derive(Debug)]
 pub struct Args<'a> {
     pub flag_sample_file_dir: Option<&'a str>,
     pub flag_preset_journal: &'a str,
     pub flag_no_vacuum: bool,
+    pub flag_no_backup: bool,
+    pub flag_allow_version_regression: bool,
+}
+
+/// Returns the `moonfire-db` version of the binary that most recently wrote a `version` row, if
+/// that (highest-`id`) row's `notes` embeds one in the format used by
+/// [`UPGRADE_NOTES`]/[`DOWNGRADE_NOTES`]; older rows are not consulted. Rows from before this convention existed, or written by `init`/`undo_last_upgrade`, don't
+/// carry a usable version and are treated the same as "no information" rather than an error.
+fn stored_binary_version(conn: &rusqlite::Connection) -> Result<Option<semver::Version>, Error> {
+    // `id` is reliable write-order here: run_initializer's downgrade path removes the rows it
+    // supersedes (see invalidate_versions_from), so unlike a naive "insert or replace" scheme,
+    // there's never a stale higher-id row left to confuse `unix_time` (1-second resolution)
+    // tiebreaking.
+    let notes: Option<String> = conn.query_row(
+        "select notes from version order by id desc limit 1", params![],
+        |row| row.get(0)).ok();
+    Ok(notes.as_ref()
+        .and_then(|n| n.rsplit(' ').next())
+        .and_then(|v| semver::Version::parse(v).ok()))
+}
+
+/// Refuses to touch the database if it was last written by a binary newer than this one, even
+/// if the numeric schema version happens to match: a newer binary may have changed the
+/// in-schema data format in a way this one doesn't understand. `flag_allow_version_regression`
+/// is an escape hatch for experts who know better.
+fn check_version_regression(args: &Args, conn: &rusqlite::Connection) -> Result<(), Error> {
+    if args.flag_allow_version_regression {
+        return Ok(());
+    }
+    let current: semver::Version = semver::Version::parse(env!("CARGO_PKG_VERSION")).unwrap();
+    if let Some(stored) = stored_binary_version(conn)? {
+        if stored > current {
+            bail!("Database was last written by moonfire-db {}, newer than this binary's {}. \
+                   Upgrade moonfire-nvr to at least {} before proceeding, or pass \
+                   --allow-version-regression if you're sure this is safe.",
+                  stored, current, stored);
+        }
+    }
+    Ok(())
+}
+
+/// Number of pages to copy per `Backup::step` call. Keeping this modest means the backup holds
+/// its read lock on the source database for a shorter stretch at a time, and lets us log
+/// progress rather than blocking silently on a large database.
+const BACKUP_PAGES_PER_STEP: std::os::raw::c_int = 100;
+
+/// Returns the path of the backup an upgrade from `from_ver` takes: a sibling of `db_path`
+/// named `<file name>.before-upgrade-v<from_ver>`.
+fn backup_path(db_path: &Path, from_ver: i32) -> PathBuf {
+    let mut name = db_path.file_name()
+        .map(|n| n.to_os_string())
+        .unwrap_or_else(|| "db".into());
+    name.push(format!(".before-upgrade-v{}", from_ver));
+    db_path.with_file_name(name)
+}
+
+/// Takes an online backup of `conn`'s database to `dst`, using SQLite's Online Backup API so
+/// the source can keep being read (though not written) while the copy proceeds.
+fn backup(conn: &rusqlite::Connection, dst: &Path) -> Result<(), Error> {
+    info!("Backing up database to {} before upgrading...", dst.display());
+    let mut dst_conn = rusqlite::Connection::open(dst)?;
+    let backup = rusqlite::backup::Backup::new(conn, &mut dst_conn)?;
+    loop {
+        backup.step(BACKUP_PAGES_PER_STEP)?;
+        let progress = backup.progress();
+        info!("...backup: {}/{} pages remaining.", progress.remaining, progress.pagecount);
+        if progress.remaining == 0 {
+            break;
+        }
+    }
+    info!("...backup complete: {}", dst.display());
+    Ok(())
+}
+
+/// A hook for initializing a fresh database and upgrading an existing one, analogous to the
+/// `ConnectionInitializer` used elsewhere for per-connection pragmas.
+///
+/// `run()` below calls these methods in order: `prepare()` once outside any transaction, then
+/// either `init()` (for a database with no `version` table at all), one `upgrade_from()` per
+/// version gap, or, when downgrading, one `revert_from()` per version gap (each within its own
+/// transaction), then `finish()` once outside any transaction.
+/// Splitting things this way lets a single implementation serve both fresh installs and
+/// in-place upgrades, and keeps version-row bookkeeping in one place rather than duplicated
+/// across every `vN_to_vN+1` module.
+trait SchemaInitializer {
+    /// Runs once, before any transaction, directly on `conn`. Used for connection-wide pragmas
+    /// (`journal_mode`, `foreign_keys`) that SQLite refuses to set within a transaction.
+    fn prepare(&self, conn: &rusqlite::Connection) -> Result<(), Error>;
+
+    /// Creates a brand new database at the current schema version, within `tx`.
+    fn init(&self, tx: &rusqlite::Transaction) -> Result<(), Error>;
+
+    /// Upgrades a database currently at `version` to `version + 1`, within `tx`. The caller
+    /// writes the `version` bookkeeping row; implementations should only touch the rest of the
+    /// schema.
+    fn upgrade_from(&self, version: i32, tx: &rusqlite::Transaction) -> Result<(), Error>;
+
+    /// Reverts a database currently at `version` back to `version - 1`, within `tx`. The caller
+    /// writes the `version` bookkeeping row; implementations should only touch the rest of the
+    /// schema. Should return an `Err` (without executing any statements) rather than reverting
+    /// a step that would lose data, so `run_initializer` can abort cleanly before touching the
+    /// database.
+    fn revert_from(&self, version: i32, tx: &rusqlite::Transaction) -> Result<(), Error>;
+
+    /// Runs once, after all transactions have committed, directly on `conn`. Used for
+    /// post-upgrade steps such as `vacuum` that SQLite also refuses to run within a transaction.
+    fn finish(&self, conn: &rusqlite::Connection) -> Result<(), Error>;
+}
+
+struct Upgrader<'a> {
+    args: &'a Args<'a>,
+}
+
+impl<'a> SchemaInitializer for Upgrader<'a> {
+    fn prepare(&self, conn: &rusqlite::Connection) -> Result<(), Error> {
+        // Enforce foreign keys. This is on by default with --features=bundled (as rusqlite
+        // compiles the SQLite3 amalgamation with -DSQLITE_DEFAULT_FOREIGN_KEYS=1). Ensure it's
+        // always on. Note that our foreign keys are immediate rather than deferred, so we have
+        // to be careful about the order of operations during the upgrade.
+        conn.execute("pragma foreign_keys = on", params![])?;
+
+        // Make the database actually durable.
+        conn.execute("pragma fullfsync = on", params![])?;
+        conn.execute("pragma synchronous = 2", params![])?;
+        set_journal_mode(conn, self.args.flag_preset_journal)?;
+        Ok(())
+    }
+
+    fn init(&self, tx: &rusqlite::Transaction) -> Result<(), Error> {
+        tx.execute_batch(include_str!("../schema.sql"))?;
+        insert_version_row(tx, db::EXPECTED_VERSION, "init")?;
+        Ok(())
+    }
+
+    fn upgrade_from(&self, version: i32, tx: &rusqlite::Transaction) -> Result<(), Error> {
+        let upgraders = [
+            v0_to_v1::run,
+            v1_to_v2::run,
+            v2_to_v3::run,
+            v3_to_v4::run,
+            v4_to_v5::run,
+        ];
+        assert_eq!(upgraders.len(), db::EXPECTED_VERSION as usize);
+        upgraders[version as usize](self.args, tx)
+    }
+
+    fn revert_from(&self, version: i32, tx: &rusqlite::Transaction) -> Result<(), Error> {
+        let reverters = [
+            v0_to_v1::revert,
+            v1_to_v2::revert,
+            v2_to_v3::revert,
+            v3_to_v4::revert,
+            v4_to_v5::revert,
+        ];
+        assert_eq!(reverters.len(), db::EXPECTED_VERSION as usize);
+        reverters[version as usize - 1](self.args, tx)
+    }
+
+    fn finish(&self, conn: &rusqlite::Connection) -> Result<(), Error> {
+        // WAL is the preferred journal mode for normal operation; it reduces the number of
+        // syncs without compromising safety.
+        set_journal_mode(conn, "wal")?;
+        if !self.args.flag_no_vacuum {
+            info!("...vacuuming database after upgrade.");
+            conn.execute_batch(r#"
+                pragma page_size = 16384;
+                vacuum;
+            "#)?;
+        }
+        info!("...done.");
+        Ok(())
+    }
 }
 
 fn set_journal_mode(conn: &rusqlite::Connection, requested: &str) -> Result<(), Error> {
@@ -61,69 +236,257 @@ fn set_journal_mode(conn: &rusqlite::Connection, requested: &str) -> Result<(),
     Ok(())
 }
 
-fn upgrade(args: &Args, target_ver: i32, conn: &mut rusqlite::Connection) -> Result<(), Error> {
-    let upgraders = [
-        v0_to_v1::run,
-        v1_to_v2::run,
-        v2_to_v3::run,
-        v3_to_v4::run,
-        v4_to_v5::run,
-    ];
+/// Invokes `f` once per chunk of `vals`, chunked so that a `?`-placeholder list built from the
+/// chunk never exceeds `conn`'s `SQLITE_LIMIT_VARIABLE_NUMBER` (typically 999, but queried
+/// rather than hard-coded so this adapts to whatever SQLite build is actually linked in).
+///
+/// This is meant for `vN_to_vN+1::run` upgraders that need to rewrite every row of a large
+/// table: naively binding every id in one `where id in (...)` statement can blow past SQLite's
+/// limit on bound parameters. `f` is called with the sub-slice for this chunk and a
+/// comma-separated string of that many `?`s, ready to splice into a statement's `in (...)`
+/// clause.
+///
+/// No `vN_to_vN+1::run` upgrader needs this yet, so it's unused outside of its own tests until
+/// a later request adds one with a large enough table to require it.
+#[allow(dead_code)]
+fn each_chunk<'a, T, F>(conn: &rusqlite::Connection, vals: &'a [T], mut f: F)
+    -> Result<(), Error>
+where F: FnMut(&'a [T], &str) -> Result<(), Error> {
+    let chunk_size =
+        std::cmp::max(1, conn.limit(rusqlite::limits::Limit::SQLITE_LIMIT_VARIABLE_NUMBER)) as usize;
+    for chunk in vals.chunks(chunk_size) {
+        let mut placeholders = String::with_capacity(2 * chunk.len());
+        for i in 0..chunk.len() {
+            if i > 0 {
+                placeholders.push(',');
+            }
+            placeholders.push('?');
+        }
+        f(chunk, &placeholders)?;
+    }
+    Ok(())
+}
 
-    {
-        assert_eq!(upgraders.len(), db::EXPECTED_VERSION as usize);
-        let old_ver =
-            conn.query_row("select max(id) from version", params![],
-                           |row| row.get(0))?;
-        if old_ver > db::EXPECTED_VERSION {
-            bail!("Database is at version {}, later than expected {}",
-                  old_ver, db::EXPECTED_VERSION);
-        } else if old_ver < 0 {
-            bail!("Database is at negative version {}!", old_ver);
+fn ensure_version_changeset_table(tx: &rusqlite::Transaction) -> Result<(), Error> {
+    tx.execute_batch(r#"
+        create table if not exists version_changeset (
+          version integer primary key references version (id),
+          changeset blob not null
+        );
+    "#)?;
+    Ok(())
+}
+
+/// Removes bookkeeping for versions `>= ver`: the `version` row(s) and any recorded changesets
+/// keyed by them. Called before writing a downgrade's own `version` row so that `id` alone
+/// (without a `unix_time` tiebreak) keeps meaning "most recently written" — otherwise the row an
+/// earlier upgrade left behind at a higher `id` would linger and get mistaken for the current
+/// version forever after.
+fn invalidate_versions_from(tx: &rusqlite::Transaction, ver: i32) -> Result<(), Error> {
+    // Only touch version_changeset if an upgrade already created it, so that a downgrade doesn't
+    // leave behind a table the earlier schema never had.
+    let has_changesets: bool = tx.query_row(r#"
+        select exists (select 1 from sqlite_master
+                       where type = 'table' and name = 'version_changeset')
+    "#, params![], |row| row.get(0))?;
+    if has_changesets {
+        tx.execute("delete from version_changeset where version >= ?", params![&ver])?;
+    }
+    tx.execute("delete from version where id >= ?", params![&ver])?;
+    Ok(())
+}
+
+/// Records that the database is now at `ver`. Uses `insert or replace` rather than a plain
+/// `insert` because a downgrade can target an id an earlier upgrade already wrote a row for
+/// (e.g. upgrade 0->5 writes ids 1 through 5, then downgrade 5->4 wants to write id 4 again); plain
+/// `insert` would hit `version`'s primary key on `id` in that case.
+fn insert_version_row(tx: &rusqlite::Transaction, ver: i32, notes: &str) -> Result<(), Error> {
+    tx.execute(r#"
+        insert or replace into version (id, unix_time, notes)
+                                values (?, cast(strftime('%s', 'now') as int32), ?)
+    "#, params![&ver, &notes])?;
+    Ok(())
+}
+
+/// Records per-step changesets (via SQLite's session extension) for audit and, failing a real
+/// `revert_from` implementation, emergency rollback. Building without `--features session`
+/// (i.e. without linking a SQLite compiled with `SQLITE_ENABLE_SESSION`) degrades gracefully to
+/// the plain, unrecorded upgrade behavior `run()` always had.
+mod changeset {
+    use super::*;
+
+    #[cfg(feature = "session")]
+    pub fn record<F>(conn: &rusqlite::Connection, f: F) -> Result<Option<Vec<u8>>, Error>
+    where F: FnOnce() -> Result<(), Error> {
+        let mut session = rusqlite::session::Session::new(conn)?;
+        session.attach(None)?;
+        f()?;
+        if session.is_empty() {
+            return Ok(None);
         }
-        info!("Upgrading database from version {} to version {}...", old_ver, target_ver);
-        set_journal_mode(&conn, args.flag_preset_journal).unwrap();
-        for ver in old_ver .. target_ver {
-            info!("...from version {} to version {}", ver, ver + 1);
+        let mut buf = Vec::new();
+        session.changeset_strm(&mut buf)?;
+        Ok(Some(buf))
+    }
+
+    #[cfg(not(feature = "session"))]
+    pub fn record<F>(_conn: &rusqlite::Connection, f: F) -> Result<Option<Vec<u8>>, Error>
+    where F: FnOnce() -> Result<(), Error> {
+        f()?;
+        Ok(None)
+    }
+
+    pub fn save(tx: &rusqlite::Transaction, ver: i32, changeset: &[u8]) -> Result<(), Error> {
+        super::ensure_version_changeset_table(tx)?;
+        tx.execute(r#"
+            insert into version_changeset (version, changeset) values (?, ?)
+        "#, params![&ver, &changeset])?;
+        Ok(())
+    }
+}
+
+/// Undoes the most recently recorded upgrade step by applying the inverse of its changeset.
+/// This is a fallback for version gaps that don't have a `revert_from` implementation; prefer
+/// [`downgrade`] when one exists, since it doesn't depend on having built with session support.
+#[cfg(feature = "session")]
+pub fn undo_last_upgrade(conn: &mut rusqlite::Connection) -> Result<(), Error> {
+    let row: Option<(i32, Vec<u8>)> = conn.query_row(r#"
+        select version, changeset from version_changeset order by version desc limit 1
+    "#, params![], |row| Ok((row.get(0)?, row.get(1)?))).ok();
+    let (ver, recorded) = match row {
+        Some(row) => row,
+        None => bail!("No recorded changeset is available to undo."),
+    };
+
+    let tx = conn.transaction()?;
+    let mut inverted = Vec::new();
+    rusqlite::session::invert_strm(&mut recorded.as_slice(), &mut inverted)?;
+    rusqlite::session::Connection::apply_strm(
+        &tx, &mut inverted.as_slice(), None::<fn(&str) -> bool>,
+        |_conflict_type, _item| rusqlite::session::ConflictAction::Abort)?;
+    invalidate_versions_from(&tx, ver)?;
+    insert_version_row(&tx, ver - 1, "undone via recorded changeset")?;
+    tx.commit()?;
+    info!("Undid upgrade to version {} using its recorded changeset.", ver);
+    Ok(())
+}
+
+#[cfg(not(feature = "session"))]
+pub fn undo_last_upgrade(_conn: &mut rusqlite::Connection) -> Result<(), Error> {
+    bail!("undoing an upgrade via changeset requires building with --features session \
+           (a SQLite compiled with SQLITE_ENABLE_SESSION)");
+}
+
+/// Returns the database's current schema version, or `None` if it has no `version` table at
+/// all (i.e., it's an empty, freshly-created file).
+fn current_version(conn: &rusqlite::Connection) -> Result<Option<i32>, Error> {
+    let has_version_table: bool = conn.query_row(r#"
+        select exists (select 1 from sqlite_master where type = 'table' and name = 'version')
+    "#, params![], |row| row.get(0))?;
+    if !has_version_table {
+        return Ok(None);
+    }
+    // run_initializer's downgrade path removes rows at and above the version it's leaving (see
+    // invalidate_versions_from), so the table never holds a stale row above the true current
+    // version and max(id) remains reliable.
+    Ok(Some(conn.query_row("select max(id) from version", params![], |row| row.get(0))?))
+}
+
+/// Runs `initializer` against `conn` to bring it to `target_ver`. `allow_downgrade` gates
+/// whether `old_ver > target_ver` is serviced by walking `revert_from` backwards or is a hard
+/// error: ordinary startup (via [`run`]) must keep refusing a database that's ahead of what
+/// this binary expects, since that's exactly the "rolled back the binary" situation an operator
+/// needs to be warned about, not have silently acted on. Only the explicit [`downgrade`] entry
+/// point passes `true`.
+fn run_initializer(initializer: &dyn SchemaInitializer, target_ver: i32, allow_downgrade: bool,
+                    conn: &mut rusqlite::Connection) -> Result<(), Error> {
+    initializer.prepare(conn)?;
+
+    if conn.is_readonly(rusqlite::DatabaseName::Main)? {
+        bail!("Database is read-only; can't upgrade to version {}.", target_ver);
+    }
+
+    match current_version(conn)? {
+        None => {
+            info!("Initializing new database at version {}...", target_ver);
             let tx = conn.transaction()?;
-            upgraders[ver as usize](&args, &tx)?;
-            tx.execute(r#"
-                insert into version (id, unix_time, notes)
-                             values (?, cast(strftime('%s', 'now') as int32), ?)
-            "#, params![&(ver + 1), &UPGRADE_NOTES])?;
+            initializer.init(&tx)?;
             tx.commit()?;
         }
+        Some(old_ver) if old_ver < 0 => {
+            bail!("Database is at negative version {}!", old_ver);
+        }
+        Some(old_ver) if old_ver > target_ver && !allow_downgrade => {
+            bail!("Database is at version {}, later than expected {}", old_ver, target_ver);
+        }
+        Some(old_ver) if old_ver > target_ver => {
+            info!("Downgrading database from version {} to version {}...", old_ver, target_ver);
+            for ver in (target_ver + 1 ..= old_ver).rev() {
+                info!("...from version {} to version {}", ver, ver - 1);
+                let tx = conn.transaction()?;
+                initializer.revert_from(ver, &tx)?;
+                invalidate_versions_from(&tx, ver)?;
+                insert_version_row(&tx, ver - 1, DOWNGRADE_NOTES)?;
+                tx.commit()?;
+            }
+        }
+        Some(old_ver) => {
+            info!("Upgrading database from version {} to version {}...", old_ver, target_ver);
+            for ver in old_ver .. target_ver {
+                info!("...from version {} to version {}", ver, ver + 1);
+                let tx = conn.transaction()?;
+                let recorded = changeset::record(&tx, || initializer.upgrade_from(ver, &tx))?;
+                insert_version_row(&tx, ver + 1, UPGRADE_NOTES)?;
+                if let Some(recorded) = recorded {
+                    changeset::save(&tx, ver + 1, &recorded)?;
+                }
+                tx.commit()?;
+            }
+        }
     }
 
+    initializer.finish(conn)?;
     Ok(())
 }
 
 pub fn run(args: &Args, conn: &mut rusqlite::Connection) -> Result<(), Error> {
-    // Enforce foreign keys. This is on by default with --features=bundled (as rusqlite
-    // compiles the SQLite3 amalgamation with -DSQLITE_DEFAULT_FOREIGN_KEYS=1). Ensure it's
-    // always on. Note that our foreign keys are immediate rather than deferred, so we have to
-    // be careful about the order of operations during the upgrade.
-    conn.execute("pragma foreign_keys = on", params![])?;
-
-    // Make the database actually durable.
-    conn.execute("pragma fullfsync = on", params![])?;
-    conn.execute("pragma synchronous = 2", params![])?;
-
-    upgrade(args, db::EXPECTED_VERSION, conn)?;
-
-    // WAL is the preferred journal mode for normal operation; it reduces the number of syncs
-    // without compromising safety.
-    set_journal_mode(&conn, "wal").unwrap();
-    if !args.flag_no_vacuum {
-        info!("...vacuuming database after upgrade.");
-        conn.execute_batch(r#"
-            pragma page_size = 16384;
-            vacuum;
-        "#).unwrap();
-    }
-    info!("...done.");
+    check_version_regression(args, conn)?;
 
-    Ok(())
+    let backup_path = if args.flag_no_backup {
+        None
+    } else {
+        match (conn.path(), current_version(conn)?) {
+            (Some(db_path), Some(from_ver)) => {
+                let path = backup_path(db_path, from_ver);
+                backup(conn, &path)?;
+                Some(path)
+            },
+
+            // No file to back up (e.g. an in-memory test database), or no existing database to
+            // lose (a fresh install), so there's nothing worth copying.
+            _ => None,
+        }
+    };
+
+    let initializer = Upgrader { args };
+    run_initializer(&initializer, db::EXPECTED_VERSION, false, conn).map_err(|e| {
+        if let Some(ref path) = backup_path {
+            info!("Upgrade failed; a pre-upgrade backup is available at {}.", path.display());
+        }
+        e
+    })
+}
+
+/// Downgrades the database schema to `target_ver`, which must not exceed the current version.
+/// Useful after rolling back to an older binary that doesn't understand a schema version a
+/// newer binary already upgraded the database to. Unlike [`run`], this is allowed to walk the
+/// schema backwards; [`run`] never does so on its own.
+pub fn downgrade(args: &Args, target_ver: i32, conn: &mut rusqlite::Connection)
+    -> Result<(), Error> {
+    check_version_regression(args, conn)?;
+
+    let initializer = Upgrader { args };
+    run_initializer(&initializer, target_ver, true, conn)
 }
 
 #[cfg(test)]
@@ -156,21 +519,254 @@ mod tests {
         let path = tmpdir.path().to_str().unwrap().to_owned();
         let mut upgraded = new_conn()?;
         upgraded.execute_batch(include_str!("v0.sql"))?;
+        upgraded.execute(r#"
+            insert into version (id, unix_time, notes) values (0, 0, 'test')
+        "#, params![])?;
+
+        let args = Args {
+            flag_sample_file_dir: Some(&path),
+            flag_preset_journal: "delete",
+            flag_no_vacuum: false,
+            flag_no_backup: true,
+            flag_allow_version_regression: false,
+        };
 
         for (ver, fresh_sql) in &[(1, Some(include_str!("v1.sql"))),
                                   (2, None),  // transitional; don't compare schemas.
                                   (3, Some(include_str!("v3.sql"))),
                                   (4, None),  // transitional; don't compare schemas.
-                                  (4, Some(include_str!("../schema.sql")))] {
-            upgrade(&Args {
-                flag_sample_file_dir: Some(&path),
-                flag_preset_journal: "delete",
-                flag_no_vacuum: false,
-            }, *ver, &mut upgraded)?;
+                                  (5, Some(include_str!("../schema.sql")))] {
+            run_initializer(&Upgrader { args: &args }, *ver, false, &mut upgraded)?;
             if let Some(f) = fresh_sql {
                 compare(&upgraded, *ver, f)?;
             }
         }
+
+        // Downgrading back down should round-trip to the schema the fresh install had at each
+        // of those versions.
+        for (ver, fresh_sql) in &[(4, None),  // transitional; don't compare schemas.
+                                  (3, Some(include_str!("v3.sql"))),
+                                  (2, None),  // transitional; don't compare schemas.
+                                  (1, Some(include_str!("v1.sql")))] {
+            run_initializer(&Upgrader { args: &args }, *ver, true, &mut upgraded)?;
+            if let Some(f) = fresh_sql {
+                compare(&upgraded, *ver, f)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn init_fresh_database() -> Result<(), Error> {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().to_str().unwrap().to_owned();
+        let mut conn = new_conn()?;
+        let args = Args {
+            flag_sample_file_dir: Some(&path),
+            flag_preset_journal: "delete",
+            flag_no_vacuum: false,
+            flag_no_backup: true,
+            flag_allow_version_regression: false,
+        };
+        run_initializer(&Upgrader { args: &args }, db::EXPECTED_VERSION, false, &mut conn)?;
+        compare(&conn, db::EXPECTED_VERSION, include_str!("../schema.sql"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_to_upgrade_readonly() {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let db_path = tmpdir.path().join("db");
+        {
+            let mut conn = rusqlite::Connection::open(&db_path).unwrap();
+            let args = Args {
+                flag_sample_file_dir: None,
+                flag_preset_journal: "delete",
+                flag_no_vacuum: true,
+                flag_no_backup: true,
+                flag_allow_version_regression: false,
+            };
+            run_initializer(&Upgrader { args: &args }, db::EXPECTED_VERSION, false, &mut conn)
+                .unwrap();
+        }
+        let mut conn = rusqlite::Connection::open_with_flags(
+            &db_path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+        let args = Args {
+            flag_sample_file_dir: None,
+            flag_preset_journal: "delete",
+            flag_no_vacuum: true,
+            flag_no_backup: true,
+            flag_allow_version_regression: false,
+        };
+        run_initializer(&Upgrader { args: &args }, db::EXPECTED_VERSION, false, &mut conn)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn run_refuses_to_downgrade() -> Result<(), Error> {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().to_str().unwrap().to_owned();
+        let mut conn = new_conn()?;
+        let args = Args {
+            flag_sample_file_dir: Some(&path),
+            flag_preset_journal: "delete",
+            flag_no_vacuum: true,
+            flag_no_backup: true,
+            flag_allow_version_regression: false,
+        };
+        run_initializer(&Upgrader { args: &args }, db::EXPECTED_VERSION, false, &mut conn)?;
+
+        // A database ahead of EXPECTED_VERSION (e.g. after rolling back to an older binary)
+        // must make plain `run()` (and the `run_initializer(..., false, ...)` it uses) bail
+        // rather than silently walk `revert_from`; only the explicit `downgrade()` entry point
+        // may do that.
+        run_initializer(&Upgrader { args: &args }, db::EXPECTED_VERSION - 1, false, &mut conn)
+            .unwrap_err();
+        run_initializer(&Upgrader { args: &args }, db::EXPECTED_VERSION - 1, true, &mut conn)?;
+        Ok(())
+    }
+
+    #[test]
+    fn run_takes_a_backup() -> Result<(), Error> {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let sample_path = tmpdir.path().to_str().unwrap().to_owned();
+        let db_path = tmpdir.path().join("db");
+        let pre_upgrade_path = tmpdir.path().join("pre-upgrade-reference");
+
+        // Get a file-backed database to an old version so run() below has a step to take.
+        {
+            let mut conn = rusqlite::Connection::open(&db_path)?;
+            let args = Args {
+                flag_sample_file_dir: Some(&sample_path),
+                flag_preset_journal: "delete",
+                flag_no_vacuum: true,
+                flag_no_backup: true,
+                flag_allow_version_regression: false,
+            };
+            run_initializer(&Upgrader { args: &args }, db::EXPECTED_VERSION - 1, false,
+                             &mut conn)?;
+        }
+
+        // Keep our own independent reference copy of the pre-upgrade file to compare the
+        // backup against, since `run()` mutates `db_path` in place.
+        std::fs::copy(&db_path, &pre_upgrade_path)?;
+
+        let args = Args {
+            flag_sample_file_dir: Some(&sample_path),
+            flag_preset_journal: "delete",
+            flag_no_vacuum: true,
+            flag_no_backup: false,
+            flag_allow_version_regression: false,
+        };
+        let mut conn = rusqlite::Connection::open(&db_path)?;
+        run(&args, &mut conn)?;
+
+        let expected_backup = backup_path(&db_path, db::EXPECTED_VERSION - 1);
+        assert!(expected_backup.is_file());
+        let backup_conn = rusqlite::Connection::open(&expected_backup)?;
+        let reference_conn = rusqlite::Connection::open(&pre_upgrade_path)?;
+        if let Some(diffs) = compare::get_diffs("backup", &backup_conn, "pre-upgrade reference",
+                                                 &reference_conn)? {
+            panic!("backup doesn't match the pre-upgrade database:\n{}", diffs);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn each_chunk_respects_variable_limit() -> Result<(), Error> {
+        let conn = new_conn()?;
+        let limit = std::cmp::max(
+            1, conn.limit(rusqlite::limits::Limit::SQLITE_LIMIT_VARIABLE_NUMBER)) as usize;
+        let vals: Vec<i64> = (0..(limit as i64 * 2 + 1)).collect();
+        let mut seen = Vec::new();
+        each_chunk(&conn, &vals, |chunk, placeholders| {
+            assert!(chunk.len() <= limit);
+            assert_eq!(placeholders.split(',').count(), chunk.len());
+            seen.extend_from_slice(chunk);
+            Ok(())
+        })?;
+        assert_eq!(seen, vals);
+        Ok(())
+    }
+
+    #[test]
+    fn each_chunk_empty_input() -> Result<(), Error> {
+        let conn = new_conn()?;
+        let vals: Vec<i64> = Vec::new();
+        let mut calls = 0;
+        each_chunk(&conn, &vals, |_chunk, _placeholders| {
+            calls += 1;
+            Ok(())
+        })?;
+        assert_eq!(calls, 0);
+        Ok(())
+    }
+
+    /// Round-trips an upgrade step through `changeset::record` and `undo_last_upgrade`, and
+    /// checks the resulting schema matches what it was pre-upgrade.
+    #[cfg(feature = "session")]
+    #[test]
+    fn undo_last_upgrade_round_trips() -> Result<(), Error> {
+        let tmpdir = tempdir::TempDir::new("moonfire-nvr-test").unwrap();
+        let path = tmpdir.path().to_str().unwrap().to_owned();
+        let db_path = tmpdir.path().join("db");
+        let pre_upgrade_path = tmpdir.path().join("pre-upgrade-reference");
+
+        let args = Args {
+            flag_sample_file_dir: Some(&path),
+            flag_preset_journal: "delete",
+            flag_no_vacuum: true,
+            flag_no_backup: true,
+            flag_allow_version_regression: false,
+        };
+
+        {
+            let mut conn = rusqlite::Connection::open(&db_path)?;
+            run_initializer(&Upgrader { args: &args }, db::EXPECTED_VERSION - 1, false,
+                             &mut conn)?;
+        }
+        std::fs::copy(&db_path, &pre_upgrade_path)?;
+
+        {
+            let mut conn = rusqlite::Connection::open(&db_path)?;
+            run_initializer(&Upgrader { args: &args }, db::EXPECTED_VERSION, false, &mut conn)?;
+        }
+
+        {
+            let mut conn = rusqlite::Connection::open(&db_path)?;
+            undo_last_upgrade(&mut conn)?;
+        }
+
+        let undone_conn = rusqlite::Connection::open(&db_path)?;
+        let reference_conn = rusqlite::Connection::open(&pre_upgrade_path)?;
+        if let Some(diffs) = compare::get_diffs("undone", &undone_conn, "pre-upgrade reference",
+                                                 &reference_conn)? {
+            panic!("undo_last_upgrade didn't restore the pre-upgrade schema:\n{}", diffs);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_newer_binary_version() -> Result<(), Error> {
+        let conn = new_conn()?;
+        conn.execute_batch("create table version (id integer primary key, unix_time integer, \
+                                                    notes text)")?;
+        conn.execute(r#"
+            insert into version (id, unix_time, notes)
+                         values (0, 0, 'upgraded using moonfire-db 9999.0.0')
+        "#, params![])?;
+
+        let args = Args {
+            flag_sample_file_dir: None,
+            flag_preset_journal: "delete",
+            flag_no_vacuum: true,
+            flag_no_backup: true,
+            flag_allow_version_regression: false,
+        };
+        check_version_regression(&args, &conn).unwrap_err();
+
+        let args = Args { flag_allow_version_regression: true, ..args };
+        check_version_regression(&args, &conn)?;
         Ok(())
     }
 }